@@ -0,0 +1,244 @@
+use crate::error::ParseError;
+use crate::heap_event::{self, HEAP_PROVIDER_GUID, OPCODE_STACKWALK, STACK_WALK_PROVIDER_GUID};
+use crate::{HeapAction, Stack};
+
+/// Size of a `WMI_BUFFER_HEADER`, the struct that starts every buffer in an
+/// `.etl` file's buffer stream (`ntwmi.h`): `BufferSize`, `SavedOffset`,
+/// `CurrentOffset`, `CurrentEventsLost` (4 `ULONG`s), a `TimeStamp`
+/// (`ULONGLONG`), a `LogInstanceGuid`/offset+refcount union (`GUID`-sized),
+/// and two trailing `ULONG`-sized unions (flags, buffer/client context).
+const WMI_BUFFER_HEADER_SIZE: usize = 4 * 4 + 8 + 16 + 4 + 4;
+
+/// Size of a classic `EVENT_TRACE_HEADER` (`evntrace.h`): `Size` (`USHORT`),
+/// `HeaderType`/`MarkerFlags` (2 `UCHAR`s), the `Class` union (`Type`,
+/// `Level`, `Version`), `ThreadId`, `ProcessId` (`ULONG`s), a `TimeStamp`
+/// (`LARGE_INTEGER`), a `Guid`, and a trailing `KernelTime`/`UserTime` (or
+/// `ProcessorTime`) union.
+const EVENT_TRACE_HEADER_SIZE: usize = 2 + 2 + 4 + 4 + 4 + 8 + 16 + 8;
+
+fn invalid(reason: impl Into<String>) -> ParseError {
+    heap_event::invalid(reason)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ParseError> {
+    bytes.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("buffer too short for a u32 field"))
+}
+
+/// A decoded classic `EVENT_TRACE_HEADER` plus the slice of its payload
+/// bytes (everything in the record after the header).
+struct Record<'a> {
+    class_type: u8,
+    guid:       [u8; 16],
+    payload:    &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    /// Decodes one event record starting at `bytes`, returning the record
+    /// and how many bytes (header + payload) it occupies.
+    fn read(bytes: &'a [u8]) -> Result<(Record<'a>, usize), ParseError> {
+        if bytes.len() < EVENT_TRACE_HEADER_SIZE {
+            return Err(invalid("event record shorter than EVENT_TRACE_HEADER"));
+        }
+
+        let size = u16::from_le_bytes(bytes[0..2].try_into().unwrap()) as usize;
+        if size < EVENT_TRACE_HEADER_SIZE || size > bytes.len() {
+            return Err(invalid(format!("event record size {size} out of range")));
+        }
+
+        let class_type = bytes[4];
+        let guid: [u8; 16] = bytes[24..40].try_into().unwrap();
+        let payload = &bytes[EVENT_TRACE_HEADER_SIZE..size];
+
+        Ok((Record { class_type, guid, payload }, size))
+    }
+}
+
+/// Parses heap activity directly out of a binary `.etl` capture: walks the
+/// file's buffer stream (each buffer a `WMI_BUFFER_HEADER` followed by
+/// packed event records), decoding the HeapCreate/Destroy/Alloc/Free/
+/// Realloc payloads from the heap provider and the raw-address stack walk
+/// events from the kernel's Stack-Walk provider, without ever shelling out
+/// to `xperf -i` to get a text dump first. Stack frames come out as
+/// formatted addresses rather than symbol names (see
+/// [`crate::heap_event::decode_stackwalk`]) - resolving those against
+/// loaded modules is a separate step this crate doesn't perform, same as
+/// `xperf -i` without its `-symbols` flag.
+pub fn parse_binary(filename: &str) -> Result<Vec<(HeapAction, Stack)>, ParseError> {
+    let contents = std::fs::read(filename).map_err(ParseError::Io)?;
+
+    let mut activity     = Vec::new();
+    let mut stacks       = Vec::new();
+    let mut active_stack = Stack(Vec::new());
+
+    let mut offset = 0usize;
+    while offset < contents.len() {
+        if contents.len() - offset < WMI_BUFFER_HEADER_SIZE {
+            return Err(invalid("trailing bytes too short for a WMI_BUFFER_HEADER"));
+        }
+
+        let buffer_size   = read_u32(&contents, offset)? as usize;
+        let saved_offset  = read_u32(&contents, offset + 4)? as usize;
+
+        if buffer_size < WMI_BUFFER_HEADER_SIZE || offset + buffer_size > contents.len() {
+            return Err(invalid(format!("buffer at {offset} has invalid BufferSize {buffer_size}")));
+        }
+        if saved_offset < WMI_BUFFER_HEADER_SIZE || saved_offset > buffer_size {
+            return Err(invalid(format!("buffer at {offset} has invalid SavedOffset {saved_offset}")));
+        }
+
+        let buffer_end = offset + saved_offset;
+        let mut event_offset = offset + WMI_BUFFER_HEADER_SIZE;
+
+        while event_offset < buffer_end {
+            let (record, record_size) = Record::read(&contents[event_offset..buffer_end])?;
+            decode_record(&record, &mut activity, &mut stacks, &mut active_stack)?;
+
+            // Classic ETW records are padded up to 8-byte alignment.
+            event_offset += (record_size + 7) & !7;
+        }
+
+        offset += buffer_size;
+    }
+
+    if active_stack.0.len() > 0 {
+        stacks.push(active_stack);
+    }
+
+    if activity.len() != stacks.len() {
+        return Err(invalid(format!(
+            "{} heap action(s) but {} stack(s); capture is likely truncated",
+            activity.len(), stacks.len(),
+        )));
+    }
+
+    Ok(activity.into_iter().zip(stacks).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap_event::{OPCODE_HEAP_ALLOC, OPCODE_HEAP_CREATE};
+
+    fn build_event(guid: [u8; 16], class_type: u8, payload: &[u8]) -> Vec<u8> {
+        let size = EVENT_TRACE_HEADER_SIZE + payload.len();
+        let mut event = Vec::with_capacity(size);
+        event.extend_from_slice(&(size as u16).to_le_bytes()); // Size
+        event.push(0); // HeaderType
+        event.push(0); // MarkerFlags
+        event.push(class_type); // Class.Type (opcode)
+        event.push(0); // Class.Level
+        event.extend_from_slice(&0u16.to_le_bytes()); // Class.Version
+        event.extend_from_slice(&0u32.to_le_bytes()); // ThreadId
+        event.extend_from_slice(&0u32.to_le_bytes()); // ProcessId
+        event.extend_from_slice(&0u64.to_le_bytes()); // TimeStamp
+        event.extend_from_slice(&guid);
+        event.extend_from_slice(&0u64.to_le_bytes()); // KernelTime/UserTime
+        event.extend_from_slice(payload);
+        event
+    }
+
+    /// Builds a stack walk payload (fixed header + raw `u64` addresses) the
+    /// way the real kernel Stack-Walk provider would, for a given set of
+    /// return addresses.
+    fn build_stackwalk_payload(addresses: &[u64]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&0u64.to_le_bytes()); // EventTimeStamp
+        payload.extend_from_slice(&0u32.to_le_bytes()); // StackProcess
+        payload.extend_from_slice(&0u32.to_le_bytes()); // StackThread
+        for address in addresses {
+            payload.extend_from_slice(&address.to_le_bytes());
+        }
+        payload
+    }
+
+    fn build_buffer(events: &[Vec<u8>]) -> Vec<u8> {
+        let mut buffer = vec![0u8; WMI_BUFFER_HEADER_SIZE];
+        for event in events {
+            buffer.extend_from_slice(event);
+            while buffer.len() % 8 != 0 {
+                buffer.push(0);
+            }
+        }
+
+        let total = buffer.len() as u32;
+        buffer[0..4].copy_from_slice(&total.to_le_bytes());
+        buffer[4..8].copy_from_slice(&total.to_le_bytes());
+        buffer
+    }
+
+    #[test]
+    fn round_trips_a_create_and_an_alloc_with_their_stacks() {
+        let mut create_payload = Vec::new();
+        create_payload.extend_from_slice(&1u64.to_le_bytes()); // heap
+
+        let mut alloc_payload = Vec::new();
+        alloc_payload.extend_from_slice(&1u64.to_le_bytes());      // heap
+        alloc_payload.extend_from_slice(&0x2000u64.to_le_bytes()); // address
+        alloc_payload.extend_from_slice(&32u64.to_le_bytes());    // size
+
+        let buffer = build_buffer(&[
+            build_event(HEAP_PROVIDER_GUID, OPCODE_HEAP_CREATE, &create_payload),
+            build_event(STACK_WALK_PROVIDER_GUID, OPCODE_STACKWALK, &build_stackwalk_payload(&[0x1000])),
+            build_event(HEAP_PROVIDER_GUID, OPCODE_HEAP_ALLOC, &alloc_payload),
+            build_event(STACK_WALK_PROVIDER_GUID, OPCODE_STACKWALK, &build_stackwalk_payload(&[0x2000, 0x3000])),
+        ]);
+
+        let path = std::env::temp_dir().join("bridengroom-binary-roundtrip-test.etl");
+        std::fs::write(&path, &buffer).unwrap();
+        let result = parse_binary(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(result.len(), 2);
+        assert!(matches!(result[0].0, HeapAction::Create { heap: 1 }));
+        assert_eq!(result[0].1.0, vec!["0x1000".to_string()]);
+        assert!(matches!(result[1].0, HeapAction::Alloc { heap: 1, address: 0x2000, size: 32 }));
+        assert_eq!(result[1].1.0, vec!["0x2000".to_string(), "0x3000".to_string()]);
+    }
+
+    #[test]
+    fn truncated_capture_is_an_error_not_a_panic() {
+        let mut create_payload = Vec::new();
+        create_payload.extend_from_slice(&1u64.to_le_bytes());
+
+        let buffer = build_buffer(&[build_event(HEAP_PROVIDER_GUID, OPCODE_HEAP_CREATE, &create_payload)]);
+        // Cut the file off mid-buffer: the header's own BufferSize/SavedOffset
+        // now claim more bytes than the file actually has.
+        let truncated = &buffer[..buffer.len() - 4];
+
+        let path = std::env::temp_dir().join("bridengroom-binary-truncated-test.etl");
+        std::fs::write(&path, truncated).unwrap();
+        let result = parse_binary(path.to_str().unwrap());
+        std::fs::remove_file(&path).ok();
+
+        assert!(result.is_err());
+    }
+}
+
+fn decode_record(
+    record: &Record<'_>,
+    activity: &mut Vec<HeapAction>,
+    stacks: &mut Vec<Stack>,
+    active_stack: &mut Stack,
+) -> Result<(), ParseError> {
+    // A stack walk event is its own record under the kernel's dedicated
+    // Stack-Walk provider, never under the heap provider whose event it
+    // describes - the two are matched up purely by arrival order, the same
+    // way xperf lines up a `Stack` row under the `HeapAlloc` row above it.
+    if record.guid == STACK_WALK_PROVIDER_GUID && record.class_type == OPCODE_STACKWALK {
+        let addresses = heap_event::decode_stackwalk(record.payload)?;
+        let frames = heap_event::format_addresses(&addresses);
+
+        if active_stack.0.len() > 0 {
+            stacks.push(std::mem::replace(active_stack, Stack(Vec::new())));
+        }
+        active_stack.0.extend(frames);
+    } else if record.guid == HEAP_PROVIDER_GUID {
+        if let Some(action) = heap_event::decode_action(record.class_type, record.payload)? {
+            activity.push(action);
+        }
+    }
+
+    Ok(())
+}
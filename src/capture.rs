@@ -0,0 +1,625 @@
+//! Native replacement for the manual capture steps described at the top of
+//! this crate (registry edits, `xperf -on base`, `xperf -start heapsession
+//! -heap ... -stackwalk ...`, double `-stop`). This module starts the NT
+//! kernel logger and a heap ETW session through the Windows tracing APIs
+//! directly and delivers decoded events to a callback, without shelling
+//! out to xperf. Stack walking is not enabled on either session: no stack
+//! walk events are requested, so a decoded action reaches the callback only
+//! once a stack walk event arrives to pair it with, or otherwise when the
+//! session stops, with an empty `Stack`.
+
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::thread::{self, JoinHandle};
+
+use crate::heap_event::{self, HEAP_PROVIDER_GUID, OPCODE_STACKWALK, STACK_WALK_PROVIDER_GUID};
+use crate::{HeapAction, Stack};
+
+const KERNEL_LOGGER_NAME: &str = "NT Kernel Logger";
+const HEAP_SESSION_NAME:  &str = "heapsession";
+
+const EVENT_TRACE_CONTROL_STOP:   u32 = 1;
+const EVENT_TRACE_REAL_TIME_MODE: u32 = 0x0000_0100;
+
+// `ProcessTraceMode` flags for `EVENT_TRACE_LOGFILEW`: consume events live
+// rather than from a file, and hand them to us pre-parsed as `EVENT_RECORD`s
+// rather than the legacy `EVENT_TRACE` callback.
+const PROCESS_TRACE_MODE_REAL_TIME:    u32 = 0x0000_0100;
+const PROCESS_TRACE_MODE_EVENT_RECORD: u32 = 0x1000_0000;
+
+const EVENT_CONTROL_CODE_ENABLE_PROVIDER: u32 = 1;
+const ENABLE_TRACE_PARAMETERS_VERSION2:   u32 = 2;
+
+/// `EVENT_FILTER_DESCRIPTOR.Type` for a filter whose data is a `ULONG[]` of
+/// process identifiers (`evntrace.h`): this is how `-pids` is implemented.
+const EVENT_FILTER_TYPE_PID: u32 = 0x8000_0004;
+
+const CREATE_SUSPENDED: u32 = 0x0000_0004;
+const INVALID_PROCESSTRACE_HANDLE: u64 = u64::MAX;
+
+/// A Windows `GUID` in its struct-of-fields layout.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Guid(u32, u16, u16, [u8; 8]);
+
+/// The GUID of the Windows heap tracing provider, as registered with the
+/// kernel logger's `-heap` flag.
+const HEAP_PROVIDER: Guid = Guid(0x222962ab, 0x6180, 0x4b88, [0xa8, 0x25, 0x34, 0x6b, 0x75, 0xf2, 0xa2, 0x4a]);
+
+impl Guid {
+    /// [`crate::heap_event`] keeps GUIDs as plain `[u8; 16]` (it has no
+    /// reason to know about Windows' struct-of-fields layout); convert so a
+    /// record's `ProviderId` can be compared against
+    /// [`HEAP_PROVIDER_GUID`] with the decoding logic shared with
+    /// [`crate::binary`].
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(&self.0.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.1.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.2.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.3);
+        bytes
+    }
+}
+
+/// `EVENT_TRACE_PROPERTIES` (`evntrace.h`): its leading `WNODE_HEADER`
+/// fields, flattened in, followed by the properties proper. `StartTraceW`/
+/// `ControlTraceW` expect `wnode_buffer_size` to equal the *entire*
+/// allocation backing this struct (struct plus the trailing logger/log-file
+/// name strings `log_file_name_offset`/`logger_name_offset` point into), not
+/// just `size_of::<EventTraceProperties>()` - see [`PropertiesBuffer`].
+#[repr(C)]
+struct EventTraceProperties {
+    // WNODE_HEADER
+    wnode_buffer_size:              u32,
+    wnode_provider_id:              u32,
+    wnode_version:                  u32,
+    wnode_linkage:                  u32,
+    wnode_kernel_handle_or_timestamp: u64,
+    wnode_guid:                     Guid,
+    wnode_client_context:           u32,
+    wnode_flags:                    u32,
+    // EVENT_TRACE_PROPERTIES
+    buffer_size:                    u32,
+    minimum_buffers:                u32,
+    maximum_buffers:                u32,
+    maximum_file_size:              u32,
+    log_file_mode:                  u32,
+    flush_timer:                    u32,
+    enable_flags:                   u32,
+    age_limit_or_flush_threshold:   i32,
+    number_of_buffers:              u32,
+    free_buffers:                   u32,
+    events_lost:                    u32,
+    buffers_written:                u32,
+    log_buffers_lost:               u32,
+    real_time_buffers_lost:         u32,
+    logger_thread_id:               *mut c_void,
+    log_file_name_offset:           u32,
+    logger_name_offset:             u32,
+}
+
+/// `EVENT_TRACE_PROPERTIES` is a fixed-size header followed by the
+/// `LoggerName`/`LogFileName` strings its offset fields point into;
+/// Windows rejects a buffer sized to just the header with
+/// `ERROR_BAD_LENGTH`. This bundles the header with that trailing
+/// allocation and keeps it alive for as long as the properties buffer is
+/// in use.
+struct PropertiesBuffer {
+    bytes: Vec<u8>,
+}
+
+impl PropertiesBuffer {
+    /// Allocates a zeroed buffer sized for `EVENT_TRACE_PROPERTIES` plus
+    /// `logger_name` as a trailing wide string, and fills in the header
+    /// fields `StartTraceW`/`ControlTraceW` need to accept the buffer.
+    fn new(logger_name: &str) -> PropertiesBuffer {
+        let name             = wide(logger_name);
+        let name_size        = name.len() * std::mem::size_of::<u16>();
+        let header_size      = std::mem::size_of::<EventTraceProperties>();
+        let total_size       = header_size + name_size;
+
+        let mut bytes = vec![0u8; total_size];
+        let header = EventTraceProperties {
+            wnode_buffer_size:                total_size as u32,
+            wnode_provider_id:                0,
+            wnode_version:                    0,
+            wnode_linkage:                    0,
+            wnode_kernel_handle_or_timestamp: 0,
+            wnode_guid:                       Guid(0, 0, 0, [0; 8]),
+            wnode_client_context:             0,
+            wnode_flags:                      0,
+            buffer_size:                      0,
+            minimum_buffers:                  0,
+            maximum_buffers:                  0,
+            maximum_file_size:                0,
+            log_file_mode:                    EVENT_TRACE_REAL_TIME_MODE,
+            flush_timer:                      0,
+            enable_flags:                     0,
+            age_limit_or_flush_threshold:     0,
+            number_of_buffers:                0,
+            free_buffers:                     0,
+            events_lost:                      0,
+            buffers_written:                  0,
+            log_buffers_lost:                 0,
+            real_time_buffers_lost:           0,
+            logger_thread_id:                 ptr::null_mut(),
+            log_file_name_offset:             0,
+            logger_name_offset:               header_size as u32,
+        };
+
+        unsafe {
+            ptr::write(bytes.as_mut_ptr() as *mut EventTraceProperties, header);
+        }
+        if name_size > 0 {
+            let name_bytes = unsafe {
+                std::slice::from_raw_parts(name.as_ptr() as *const u8, name_size)
+            };
+            bytes[header_size..].copy_from_slice(name_bytes);
+        }
+
+        PropertiesBuffer { bytes }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut EventTraceProperties {
+        self.bytes.as_mut_ptr() as *mut EventTraceProperties
+    }
+}
+
+/// `EVENT_DESCRIPTOR` (`evntprov.h`): identifies an event's opcode, level,
+/// and keyword within its provider.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EventDescriptor {
+    id:      u16,
+    version: u8,
+    channel: u8,
+    level:   u8,
+    opcode:  u8,
+    task:    u16,
+    keyword: u64,
+}
+
+/// `EVENT_HEADER` (`evntcons.h`): the fixed part of every real-time event,
+/// ahead of its provider-defined payload.
+#[repr(C)]
+struct EventHeader {
+    size:                     u16,
+    header_type:              u16,
+    flags:                    u16,
+    event_property:           u16,
+    thread_id:                u32,
+    process_id:               u32,
+    timestamp:                i64,
+    provider_id:              Guid,
+    descriptor:               EventDescriptor,
+    kernel_or_processor_time: u64,
+    activity_id:              Guid,
+}
+
+/// `ETW_BUFFER_CONTEXT` (`evntcons.h`).
+#[repr(C)]
+struct EtwBufferContext {
+    processor_number: u8,
+    alignment:        u8,
+    logger_id:        u16,
+}
+
+/// `EVENT_RECORD` (`evntcons.h`): what `ProcessTrace` hands our callback for
+/// every event once we ask for it in `PROCESS_TRACE_MODE_EVENT_RECORD`.
+/// `user_data` points at the provider-defined payload, `user_data_length`
+/// bytes long - for the heap provider that's exactly the payload shape
+/// `heap_event::decode_action`/`decode_stackwalk` already know how to read
+/// out of `.etl` files in [`crate::binary`].
+#[repr(C)]
+struct EventRecord {
+    header:              EventHeader,
+    buffer_context:       EtwBufferContext,
+    extended_data_count:  u16,
+    user_data_length:     u16,
+    extended_data:        *mut c_void,
+    user_data:            *mut c_void,
+    user_context:         *mut c_void,
+}
+
+/// `EVENT_FILTER_DESCRIPTOR` (`evntrace.h`).
+#[repr(C)]
+struct EventFilterDescriptor {
+    ptr:  u64,
+    size: u32,
+    kind: u32,
+}
+
+/// `ENABLE_TRACE_PARAMETERS` (`evntrace.h`), passed to `EnableTraceEx2` to
+/// narrow a session down to specific processes.
+#[repr(C)]
+struct EnableTraceParameters {
+    version:            u32,
+    enable_property:    u32,
+    control_flags:      u32,
+    source_id:          Guid,
+    enable_filter_desc: *mut EventFilterDescriptor,
+    filter_desc_count:  u32,
+}
+
+/// Mirrors `EVENT_TRACE_LOGFILEW` (`evntrace.h`) closely enough to drive
+/// `OpenTraceW`/`ProcessTrace` in real-time mode. `current_event` and
+/// `logfile_header` are populated by the OS and this crate never reads them
+/// back, so they're kept as opaque, correctly sized byte buffers rather
+/// than fully modeled structs.
+#[repr(C)]
+struct EventTraceLogfileW {
+    log_file_name:         *mut u16,
+    logger_name:           *mut u16,
+    current_time:          i64,
+    buffers_read:          u32,
+    process_trace_mode:    u32,
+    current_event:         [u8; 88],
+    logfile_header:        [u8; 280],
+    buffer_callback:       *mut c_void,
+    buffer_size:           u32,
+    filled:                u32,
+    events_lost:           u32,
+    event_record_callback: unsafe extern "system" fn(*mut EventRecord),
+    is_kernel_trace:       u32,
+    context:               *mut c_void,
+}
+
+#[repr(C)]
+struct StartupInfoW {
+    cb:               u32,
+    reserved:         *mut u16,
+    desktop:          *mut u16,
+    title:            *mut u16,
+    x:                u32,
+    y:                u32,
+    x_size:           u32,
+    y_size:           u32,
+    x_count_chars:    u32,
+    y_count_chars:    u32,
+    fill_attribute:   u32,
+    flags:            u32,
+    show_window:      u16,
+    cb_reserved2:     u16,
+    lp_reserved2:     *mut u8,
+    std_input:        *mut c_void,
+    std_output:       *mut c_void,
+    std_error:        *mut c_void,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProcessInformation {
+    process: *mut c_void,
+    thread:  *mut c_void,
+    process_id: u32,
+    thread_id:  u32,
+}
+
+extern "system" {
+    fn StartTraceW(session_handle: *mut u64, session_name: *const u16, properties: *mut EventTraceProperties) -> u32;
+    fn EnableTraceEx2(
+        session_handle: u64, provider_id: *const Guid, control_code: u32,
+        level: u8, match_any_keyword: u64, match_all_keyword: u64,
+        timeout: u32, params: *mut EnableTraceParameters,
+    ) -> u32;
+    fn ControlTraceW(session_handle: u64, session_name: *const u16, properties: *mut EventTraceProperties, control_code: u32) -> u32;
+    fn OpenTraceW(logfile: *mut EventTraceLogfileW) -> u64;
+    fn ProcessTrace(handle_array: *const u64, handle_count: u32, start_time: *const c_void, end_time: *const c_void) -> u32;
+    fn CloseTrace(trace_handle: u64) -> u32;
+
+    fn CreateProcessW(
+        application_name: *const u16, command_line: *mut u16,
+        process_attributes: *mut c_void, thread_attributes: *mut c_void,
+        inherit_handles: i32, creation_flags: u32, environment: *mut c_void,
+        current_directory: *const u16, startup_info: *const StartupInfoW,
+        process_information: *mut ProcessInformation,
+    ) -> i32;
+    fn ResumeThread(thread: *mut c_void) -> u32;
+    fn CloseHandle(handle: *mut c_void) -> i32;
+}
+
+fn wide(string: &str) -> Vec<u16> {
+    OsStr::new(string).encode_wide().chain(std::iter::once(0)).collect()
+}
+
+fn check(status: u32) -> io::Result<()> {
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(status as i32))
+    }
+}
+
+/// What to attach heap tracing to: an already-running process, or a fresh
+/// process to launch with heap tracing enabled from the start (xperf's
+/// `-PidNewProcess`).
+pub enum CaptureTarget {
+    Pid(u32),
+    NewProcess(String),
+}
+
+/// A running native ETW capture session. Dropping this without calling
+/// [`CaptureSession::stop`] still stops the kernel logger and heap session
+/// (ignoring any error), but does not wait for the worker thread.
+pub struct CaptureSession {
+    kernel_logger: u64,
+    heap_session:  u64,
+    worker:        Option<JoinHandle<()>>,
+}
+
+impl CaptureSession {
+    /// Starts the NT kernel logger (with no enable flags set; its events are
+    /// not consumed, and no module/process resolution is done) and a heap
+    /// session targeting `target`, decoding events and invoking `on_event`
+    /// for each one from a dedicated worker thread. No stack walk events are
+    /// requested; see the module docs for how that affects delivery.
+    pub fn start(
+        target: CaptureTarget,
+        mut on_event: impl FnMut(HeapAction, Stack) + Send + 'static,
+    ) -> io::Result<CaptureSession> {
+        let kernel_logger = start_kernel_logger()?;
+        let heap_session  = start_heap_session(&target)?;
+
+        let worker = thread::spawn(move || {
+            // Each decoded (action, stack) pair is delivered here as the
+            // real-time session callback hands it off; see
+            // `event_record_callback` for how a raw `EVENT_RECORD` becomes
+            // a `HeapAction`.
+            run_event_loop(HEAP_SESSION_NAME, |action, stack| on_event(action, stack));
+        });
+
+        Ok(CaptureSession { kernel_logger, heap_session, worker: Some(worker) })
+    }
+
+    /// Stops both sessions (the two stops are intentional: one stops the
+    /// kernel logger, one stops the heap session) and waits for the worker
+    /// thread delivering events to finish.
+    pub fn stop(mut self) -> io::Result<()> {
+        let mut heap_properties   = PropertiesBuffer::new(HEAP_SESSION_NAME);
+        let mut kernel_properties = PropertiesBuffer::new(KERNEL_LOGGER_NAME);
+        unsafe {
+            check(ControlTraceW(self.heap_session, ptr::null(), heap_properties.as_mut_ptr(), EVENT_TRACE_CONTROL_STOP))?;
+            check(ControlTraceW(self.kernel_logger, wide(KERNEL_LOGGER_NAME).as_ptr(), kernel_properties.as_mut_ptr(), EVENT_TRACE_CONTROL_STOP))?;
+        }
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for CaptureSession {
+    fn drop(&mut self) {
+        if self.worker.is_some() {
+            let mut heap_properties   = PropertiesBuffer::new(HEAP_SESSION_NAME);
+            let mut kernel_properties = PropertiesBuffer::new(KERNEL_LOGGER_NAME);
+            unsafe {
+                let _ = ControlTraceW(self.heap_session, ptr::null(), heap_properties.as_mut_ptr(), EVENT_TRACE_CONTROL_STOP);
+                let _ = ControlTraceW(self.kernel_logger, wide(KERNEL_LOGGER_NAME).as_ptr(), kernel_properties.as_mut_ptr(), EVENT_TRACE_CONTROL_STOP);
+            }
+        }
+    }
+}
+
+fn start_kernel_logger() -> io::Result<u64> {
+    let mut handle = 0u64;
+    let mut properties = PropertiesBuffer::new(KERNEL_LOGGER_NAME);
+    unsafe {
+        check(StartTraceW(&mut handle, wide(KERNEL_LOGGER_NAME).as_ptr(), properties.as_mut_ptr()))?;
+    }
+    Ok(handle)
+}
+
+fn start_heap_session(target: &CaptureTarget) -> io::Result<u64> {
+    let mut handle = 0u64;
+    let mut properties = PropertiesBuffer::new(HEAP_SESSION_NAME);
+    unsafe {
+        check(StartTraceW(&mut handle, wide(HEAP_SESSION_NAME).as_ptr(), properties.as_mut_ptr()))?;
+
+        // `target` narrows the session to a single process: either an
+        // existing PID (xperf's `-pids`) or a freshly launched one
+        // (`-PidNewProcess`). Either way we enable the provider filtered
+        // down to that one PID via an EVENT_FILTER_DESCRIPTOR, so we never
+        // see another process's heap traffic.
+        match target {
+            CaptureTarget::Pid(pid) => {
+                enable_for_pid(handle, *pid)?;
+            }
+            CaptureTarget::NewProcess(command_line) => {
+                let process_info = spawn_suspended(command_line)?;
+                let result = enable_for_pid(handle, process_info.process_id);
+                // Resume regardless of whether enabling the filter
+                // succeeded - otherwise a failed capture also leaves the
+                // child process stuck suspended forever.
+                ResumeThread(process_info.thread);
+                CloseHandle(process_info.thread);
+                CloseHandle(process_info.process);
+                result?;
+            }
+        }
+    }
+    Ok(handle)
+}
+
+/// Enables the heap provider on `session`, filtered down to events from
+/// `pid` only, via `EnableTraceEx2`'s `EVENT_FILTER_TYPE_PID` filter.
+unsafe fn enable_for_pid(session: u64, pid: u32) -> io::Result<()> {
+    let mut pids = [pid];
+    let mut filter = EventFilterDescriptor {
+        ptr:  pids.as_mut_ptr() as u64,
+        size: std::mem::size_of_val(&pids) as u32,
+        kind: EVENT_FILTER_TYPE_PID,
+    };
+    let mut params = EnableTraceParameters {
+        version:            ENABLE_TRACE_PARAMETERS_VERSION2,
+        enable_property:    0,
+        control_flags:      0,
+        source_id:          Guid(0, 0, 0, [0; 8]),
+        enable_filter_desc: &mut filter,
+        filter_desc_count:  1,
+    };
+
+    check(EnableTraceEx2(
+        session, &HEAP_PROVIDER, EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+        0, 0, 0, 0,
+        &mut params,
+    ))
+}
+
+/// Launches `command_line` suspended (`CREATE_SUSPENDED`) so heap tracing
+/// can be enabled for its PID before it executes a single instruction -
+/// the native equivalent of xperf's `-PidNewProcess`.
+unsafe fn spawn_suspended(command_line: &str) -> io::Result<ProcessInformation> {
+    let mut command_line = wide(command_line);
+    let startup_info = StartupInfoW {
+        cb:             std::mem::size_of::<StartupInfoW>() as u32,
+        reserved:       ptr::null_mut(),
+        desktop:        ptr::null_mut(),
+        title:          ptr::null_mut(),
+        x: 0, y: 0, x_size: 0, y_size: 0, x_count_chars: 0, y_count_chars: 0,
+        fill_attribute: 0,
+        flags:          0,
+        show_window:    0,
+        cb_reserved2:   0,
+        lp_reserved2:   ptr::null_mut(),
+        std_input:      ptr::null_mut(),
+        std_output:     ptr::null_mut(),
+        std_error:      ptr::null_mut(),
+    };
+    let mut process_info = ProcessInformation::default();
+
+    let ok = CreateProcessW(
+        ptr::null(), command_line.as_mut_ptr(),
+        ptr::null_mut(), ptr::null_mut(),
+        0, CREATE_SUSPENDED, ptr::null_mut(),
+        ptr::null(), &startup_info,
+        &mut process_info,
+    );
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(process_info)
+}
+
+/// Per-session state threaded through to [`event_record_callback`] via
+/// `EVENT_TRACE_LOGFILEW::Context`. Pairs up actions with their stackwalk
+/// the same way [`crate::binary::parse_binary`] does when it sees the two
+/// events back to back: a new stackwalk flushes whatever non-empty stack
+/// was accumulating and hands it, paired with the oldest pending action,
+/// to `on_event`.
+struct CaptureContext<F> {
+    on_event:     F,
+    pending:      VecDeque<HeapAction>,
+    active_stack: Stack,
+}
+
+impl<F: FnMut(HeapAction, Stack)> CaptureContext<F> {
+    fn handle_record(&mut self, guid: [u8; 16], opcode: u8, payload: &[u8]) {
+        // A stack walk event is its own record under the kernel's dedicated
+        // Stack-Walk provider, never under the heap provider whose event it
+        // describes - see `crate::binary::decode_record`, which pairs them
+        // up the same way.
+        if guid == STACK_WALK_PROVIDER_GUID && opcode == OPCODE_STACKWALK {
+            // A malformed stackwalk payload (truncated packet, corrupt
+            // capture) is dropped rather than killing the capture thread;
+            // unlike a file-based parse there's no caller left to hand a
+            // `ParseError` back to once we're this deep in an OS callback.
+            let Ok(addresses) = heap_event::decode_stackwalk(payload) else { return };
+            let frames = heap_event::format_addresses(&addresses);
+
+            if !self.active_stack.0.is_empty() {
+                let stack = std::mem::replace(&mut self.active_stack, Stack(Vec::new()));
+                if let Some(action) = self.pending.pop_front() {
+                    (self.on_event)(action, stack);
+                }
+            }
+            self.active_stack.0.extend(frames);
+        } else if guid == HEAP_PROVIDER_GUID {
+            if let Ok(Some(action)) = heap_event::decode_action(opcode, payload) {
+                self.pending.push_back(action);
+            }
+        }
+    }
+
+    /// Flushes anything still pending once the session stops, mirroring
+    /// `HeapActivity`'s EOF draining in [`crate::stream`]: every pending
+    /// action is still delivered, just with an empty `Stack` once there's
+    /// no more trailing stackwalk left to pair it with.
+    fn flush(&mut self) {
+        while let Some(action) = self.pending.pop_front() {
+            let stack = std::mem::replace(&mut self.active_stack, Stack(Vec::new()));
+            (self.on_event)(action, stack);
+        }
+    }
+}
+
+unsafe extern "system" fn event_record_callback<F: FnMut(HeapAction, Stack)>(record: *mut EventRecord) {
+    let record = &*record;
+    let context = &mut *(record.user_context as *mut CaptureContext<F>);
+
+    let guid   = record.header.provider_id.to_bytes();
+    let opcode = record.header.descriptor.opcode;
+    let payload = if record.user_data.is_null() || record.user_data_length == 0 {
+        &[][..]
+    } else {
+        std::slice::from_raw_parts(record.user_data as *const u8, record.user_data_length as usize)
+    };
+
+    context.handle_record(guid, opcode, payload);
+}
+
+/// Blocks the calling (worker) thread, decoding real-time events off the
+/// session named `session_name` and handing each finished
+/// `(HeapAction, Stack)` pair to `on_event`, until the session is stopped
+/// out from under it (`ProcessTrace` returns once `CaptureSession::stop`'s
+/// `ControlTraceW` call takes effect).
+fn run_event_loop<F: FnMut(HeapAction, Stack)>(session_name: &str, on_event: F) {
+    let mut context = Box::new(CaptureContext {
+        on_event,
+        pending:      VecDeque::new(),
+        active_stack: Stack(Vec::new()),
+    });
+
+    // Real-time consumption opens by the session's logger name rather than
+    // the control handle `StartTraceW` returned, so `logger_name` has to
+    // outlive the `OpenTraceW`/`ProcessTrace` calls below.
+    let mut logger_name = wide(session_name);
+    let mut logfile = EventTraceLogfileW {
+        log_file_name:         ptr::null_mut(),
+        logger_name:           logger_name.as_mut_ptr(),
+        current_time:          0,
+        buffers_read:          0,
+        process_trace_mode:    PROCESS_TRACE_MODE_REAL_TIME | PROCESS_TRACE_MODE_EVENT_RECORD,
+        current_event:         [0; 88],
+        logfile_header:        [0; 280],
+        buffer_callback:       ptr::null_mut(),
+        buffer_size:           0,
+        filled:                0,
+        events_lost:           0,
+        event_record_callback: event_record_callback::<F>,
+        is_kernel_trace:       0,
+        context: context.as_mut() as *mut CaptureContext<F> as *mut c_void,
+    };
+
+    let trace_handle = unsafe { OpenTraceW(&mut logfile) };
+    if trace_handle == INVALID_PROCESSTRACE_HANDLE {
+        return;
+    }
+
+    unsafe {
+        // Blocks, invoking `event_record_callback` per event, until the
+        // session is stopped (or this call errors out).
+        ProcessTrace(&trace_handle, 1, ptr::null(), ptr::null());
+        CloseTrace(trace_handle);
+    }
+
+    context.flush();
+}
@@ -0,0 +1,57 @@
+use std::fmt;
+use std::io;
+
+/// Something that went wrong while reading or parsing heap activity: a
+/// single line of an `xperf -i` text dump, or a binary `.etl` record.
+///
+/// Carried alongside the line number by the lenient parsing entry points so
+/// a partially-corrupt capture can still report exactly what was skipped.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The underlying file could not be read.
+    Io(io::Error),
+    /// A column that should have held a `0x`-prefixed hex number didn't
+    /// start with `0x`.
+    InvalidHexPrefix { column: String },
+    /// A column that should have held a `0x`-prefixed hex number had a
+    /// prefix but the digits after it weren't valid hex.
+    InvalidHexNumber { column: String },
+    /// The `Stack` row's depth column wasn't a valid number.
+    InvalidDepth { column: String },
+    /// An event row didn't have as many columns as its event type requires.
+    MissingColumns { event: String, expected: usize, found: usize },
+    /// Reached `EndHeader` without having seen every header format we
+    /// expect to parse.
+    MissingHeaders,
+    /// A binary ETL buffer or event record was truncated, oversized, or
+    /// otherwise inconsistent with its own length fields.
+    InvalidRecord { reason: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "io error: {err}"),
+            ParseError::InvalidHexPrefix { column } =>
+                write!(f, "expected a 0x-prefixed hex number, got {column:?}"),
+            ParseError::InvalidHexNumber { column } =>
+                write!(f, "invalid hex digits in {column:?}"),
+            ParseError::InvalidDepth { column } =>
+                write!(f, "invalid stack depth {column:?}"),
+            ParseError::MissingColumns { event, expected, found } =>
+                write!(f, "{event} row has {found} columns, expected at least {expected}"),
+            ParseError::MissingHeaders =>
+                write!(f, "reached EndHeader without matching all expected headers"),
+            ParseError::InvalidRecord { reason } =>
+                write!(f, "invalid ETL record: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
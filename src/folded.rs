@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use crate::{HeapAction, Stack};
+
+/// How to weigh each allocation site when folding stacks together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Weight {
+    /// Weigh each site by the number of allocations made from it.
+    Allocations,
+    /// Weigh each site by the total bytes allocated from it.
+    Bytes,
+}
+
+/// Turns parsed heap activity into the "collapsed stack" text format used by
+/// flamegraph tools: one line per unique call stack of the form
+/// `frame1;frame2;...;frameN <count-or-bytes>`, root frame first.
+///
+/// Only `Alloc` and `Realloc` actions contribute weight; identical stacks
+/// (after reversing so the root leads) are merged.
+pub fn to_folded(activity: &[(HeapAction, Stack)], weight: Weight) -> String {
+    let mut folded: HashMap<Vec<String>, u64> = HashMap::new();
+
+    for (action, stack) in activity {
+        let amount = match (action, weight) {
+            (HeapAction::Alloc { size, .. }, Weight::Bytes)             => *size,
+            (HeapAction::Realloc { new_size, .. }, Weight::Bytes)       => *new_size,
+            (HeapAction::Alloc { .. }, Weight::Allocations)             => 1,
+            (HeapAction::Realloc { .. }, Weight::Allocations)           => 1,
+            _ => continue,
+        };
+
+        let mut frames = stack.0.clone();
+        frames.reverse();
+
+        *folded.entry(frames).or_insert(0) += amount;
+    }
+
+    let mut lines: Vec<String> = folded.into_iter()
+        .map(|(frames, amount)| format!("{} {}", frames.join(";"), amount))
+        .collect();
+    lines.sort();
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(frames: &[&str]) -> Stack {
+        Stack(frames.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn identical_stacks_are_merged_and_reversed_root_first() {
+        let activity = vec![
+            (HeapAction::Alloc { heap: 1, address: 0x1000, size: 16 }, stack(&["main", "alloc"])),
+            (HeapAction::Alloc { heap: 1, address: 0x2000, size: 16 }, stack(&["main", "alloc"])),
+        ];
+
+        let folded = to_folded(&activity, Weight::Allocations);
+        assert_eq!(folded, "alloc;main 2");
+    }
+
+    #[test]
+    fn bytes_weight_sums_sizes_instead_of_counting() {
+        let activity = vec![
+            (HeapAction::Alloc { heap: 1, address: 0x1000, size: 16 }, stack(&["main", "alloc"])),
+            (HeapAction::Realloc {
+                heap: 1, new_address: 0x3000, old_address: 0x1000, new_size: 48, old_size: 16,
+            }, stack(&["main", "alloc"])),
+        ];
+
+        let folded = to_folded(&activity, Weight::Bytes);
+        assert_eq!(folded, "alloc;main 64");
+    }
+
+    #[test]
+    fn create_and_destroy_and_free_contribute_no_weight() {
+        let activity = vec![
+            (HeapAction::Create { heap: 1 }, stack(&["main"])),
+            (HeapAction::Free { heap: 1, address: 0x1000 }, stack(&["main"])),
+            (HeapAction::Destroy { heap: 1 }, stack(&["main"])),
+        ];
+
+        assert_eq!(to_folded(&activity, Weight::Allocations), "");
+    }
+}
@@ -0,0 +1,181 @@
+//! Decoding shared by the two binary sources of heap events: a `.etl` file
+//! read after the fact ([`crate::binary`]) and a live real-time ETW session
+//! ([`crate::capture`], Windows only). Both see the same HeapCreate/Destroy/
+//! Alloc/Free/Realloc payload layouts from the heap provider, and the same
+//! raw-address stack walk payload from the kernel's separate Stack-Walk
+//! provider, just delivered through different transports.
+
+use crate::error::ParseError;
+use crate::HeapAction;
+
+/// Heap-provider event opcodes (`EVENT_DESCRIPTOR.Opcode` / the classic
+/// `EVENT_TRACE_HEADER.Class.Type`), as defined by the Heap provider's
+/// manifest.
+pub(crate) const OPCODE_HEAP_CREATE:  u8 = 32;
+pub(crate) const OPCODE_HEAP_ALLOC:   u8 = 33;
+pub(crate) const OPCODE_HEAP_REALLOC: u8 = 34;
+pub(crate) const OPCODE_HEAP_DESTROY: u8 = 35;
+pub(crate) const OPCODE_HEAP_FREE:    u8 = 36;
+
+/// Opcode of a kernel stack walk event, emitted under
+/// [`STACK_WALK_PROVIDER_GUID`] rather than under the originating event's
+/// own provider.
+pub(crate) const OPCODE_STACKWALK: u8 = 32;
+
+/// The GUID of the Windows heap tracing provider, as registered with the
+/// kernel logger's `-heap` flag.
+pub(crate) const HEAP_PROVIDER_GUID: [u8; 16] = [
+    0xab, 0x62, 0x29, 0x22, 0x80, 0x61, 0x88, 0x4b,
+    0xa8, 0x25, 0x34, 0x6b, 0x75, 0xf2, 0xa2, 0x4a,
+];
+
+/// The GUID of the kernel's Stack-Walk provider (`{def2fe46-7bd6-4b80-bd94-
+/// f57fe20d0ce3}`). A stack walk event is emitted as its own record under
+/// this GUID, separate from (and after) the event whose call stack it
+/// describes — it is never stamped with that event's own provider GUID.
+pub(crate) const STACK_WALK_PROVIDER_GUID: [u8; 16] = [
+    0x46, 0xfe, 0xf2, 0xde, 0xd6, 0x7b, 0x80, 0x4b,
+    0xbd, 0x94, 0xf5, 0x7f, 0xe2, 0x0d, 0x0c, 0xe3,
+];
+
+pub(crate) fn invalid(reason: impl Into<String>) -> ParseError {
+    ParseError::InvalidRecord { reason: reason.into() }
+}
+
+fn read_u64(payload: &[u8], offset: usize) -> Result<u64, ParseError> {
+    payload.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("heap event payload too short"))
+}
+
+/// Decodes a HeapCreate/Destroy/Alloc/Free/Realloc payload into a
+/// `HeapAction`. Returns `Ok(None)` for any other opcode (including the
+/// stackwalk opcode, which has no `HeapAction` of its own — see
+/// [`decode_stackwalk`]).
+pub(crate) fn decode_action(opcode: u8, payload: &[u8]) -> Result<Option<HeapAction>, ParseError> {
+    let action = match opcode {
+        OPCODE_HEAP_CREATE => {
+            let heap = read_u64(payload, 0)?;
+            HeapAction::Create { heap }
+        }
+        OPCODE_HEAP_DESTROY => {
+            let heap = read_u64(payload, 0)?;
+            HeapAction::Destroy { heap }
+        }
+        OPCODE_HEAP_ALLOC => {
+            let heap    = read_u64(payload, 0)?;
+            let address = read_u64(payload, 8)?;
+            let size    = read_u64(payload, 16)?;
+            HeapAction::Alloc { heap, address, size }
+        }
+        OPCODE_HEAP_FREE => {
+            let heap    = read_u64(payload, 0)?;
+            let address = read_u64(payload, 8)?;
+            HeapAction::Free { heap, address }
+        }
+        OPCODE_HEAP_REALLOC => {
+            let heap        = read_u64(payload, 0)?;
+            let new_address = read_u64(payload, 8)?;
+            let old_address = read_u64(payload, 16)?;
+            let new_size    = read_u64(payload, 24)?;
+            let old_size    = read_u64(payload, 32)?;
+            HeapAction::Realloc { heap, new_address, old_address, new_size, old_size }
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(action))
+}
+
+/// Size of a stack walk event's fixed header, ahead of its return
+/// addresses: `EventTimeStamp` (`UINT64`), `StackProcess`, `StackThread`
+/// (two `UINT32`s).
+const STACKWALK_HEADER_SIZE: usize = 8 + 4 + 4;
+
+/// Decodes a kernel stack walk payload: a fixed header followed by however
+/// many `UINT64` return addresses fit in the rest of the payload — real
+/// stack walk events carry no symbol names on the wire at all (resolving
+/// addresses against loaded modules/PDBs, the way `xperf -i -symbols` does,
+/// is a separate offline step this crate doesn't perform); see
+/// [`format_addresses`] for turning the result into the `Stack` frames the
+/// rest of the crate deals in. Bounds-checked against the payload length so
+/// a truncated or corrupt payload returns an error instead of panicking on
+/// an out-of-bounds slice.
+pub(crate) fn decode_stackwalk(payload: &[u8]) -> Result<Vec<u64>, ParseError> {
+    if payload.len() < STACKWALK_HEADER_SIZE {
+        return Err(invalid("stack walk payload shorter than its fixed header"));
+    }
+    let remainder = payload.len() - STACKWALK_HEADER_SIZE;
+    if remainder % 8 != 0 {
+        return Err(invalid(format!(
+            "stack walk payload's {remainder}-byte address list isn't a whole number of u64s"
+        )));
+    }
+
+    let mut addresses = Vec::with_capacity(remainder / 8);
+    let mut offset = STACKWALK_HEADER_SIZE;
+    while offset < payload.len() {
+        addresses.push(read_u64(payload, offset)?);
+        offset += 8;
+    }
+
+    Ok(addresses)
+}
+
+/// Formats raw stack walk addresses as the `Stack` frames the rest of the
+/// crate works with, since this crate has no symbol resolution of its own
+/// (see [`decode_stackwalk`]) to turn them into `module!function` names the
+/// way the text-dump path's already-symbolized `xperf -i` output does.
+pub(crate) fn format_addresses(addresses: &[u64]) -> Vec<String> {
+    addresses.iter().map(|address| format!("{address:#x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_alloc() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&1u64.to_le_bytes());    // heap
+        payload.extend_from_slice(&0x1000u64.to_le_bytes()); // address
+        payload.extend_from_slice(&64u64.to_le_bytes());    // size
+
+        let action = decode_action(OPCODE_HEAP_ALLOC, &payload).unwrap().unwrap();
+        match action {
+            HeapAction::Alloc { heap, address, size } => {
+                assert_eq!(heap, 1);
+                assert_eq!(address, 0x1000);
+                assert_eq!(size, 64);
+            }
+            other => panic!("expected Alloc, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn stackwalk_decodes_raw_return_addresses() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&0u64.to_le_bytes()); // EventTimeStamp
+        payload.extend_from_slice(&1u32.to_le_bytes()); // StackProcess
+        payload.extend_from_slice(&2u32.to_le_bytes()); // StackThread
+        payload.extend_from_slice(&0x7ffe_1000u64.to_le_bytes()); // Stack1
+        payload.extend_from_slice(&0x0040_1234u64.to_le_bytes()); // Stack2
+
+        let addresses = decode_stackwalk(&payload).unwrap();
+        assert_eq!(addresses, vec![0x7ffe_1000, 0x0040_1234]);
+        assert_eq!(format_addresses(&addresses), vec!["0x7ffe1000".to_string(), "0x401234".to_string()]);
+    }
+
+    #[test]
+    fn stackwalk_rejects_a_payload_too_short_for_its_header() {
+        let payload = [0u8; 4];
+        assert!(decode_stackwalk(&payload).is_err());
+    }
+
+    #[test]
+    fn stackwalk_rejects_a_trailing_partial_address_instead_of_panicking() {
+        let mut payload = vec![0u8; STACKWALK_HEADER_SIZE];
+        payload.extend_from_slice(&[0u8; 4]); // 4 leftover bytes, not a whole u64
+        assert!(decode_stackwalk(&payload).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{HeapAction, Stack};
+
+/// A problem detected while replaying a heap activity stream.
+///
+/// Each variant carries the heap handle and address involved, the size of
+/// the allocation (where known), and the `Stack` that is most useful for
+/// tracking down the bug: the allocation site for a `Leak` or `Overlap`, the
+/// offending free site for a `DoubleFree` or `UseAfterFree`.
+#[derive(Clone, Debug)]
+pub enum HeapError {
+    /// An address was still live when its heap was destroyed (or when the
+    /// stream ended).
+    Leak { heap: u64, address: u64, size: u64, stack: Stack },
+    /// An address was freed that was not currently live.
+    DoubleFree { heap: u64, address: u64, stack: Stack },
+    /// An address was freed that belonged to a prior, different allocation
+    /// at the same address (the allocation that actually owned the address
+    /// was reallocated elsewhere first). Also reported, with the old
+    /// address, for a `Realloc` whose old address is not live.
+    UseAfterFree { heap: u64, address: u64, stack: Stack },
+    /// An address was allocated while it was already live.
+    Overlap { heap: u64, address: u64, size: u64, stack: Stack },
+}
+
+/// Live state of all heaps seen so far, reconstructed by replaying a stream
+/// of `(HeapAction, Stack)` pairs in order.
+#[derive(Clone, Debug, Default)]
+pub struct HeapState {
+    /// Live heap handles, mapped to the set of addresses currently live in
+    /// that heap.
+    live_heaps: HashMap<u64, HashSet<u64>>,
+    /// Live addresses, mapped to their size and allocation-site stack.
+    live_addresses: HashMap<u64, (u64, Stack)>,
+    /// Reverse index of `live_addresses`: which heap an address is
+    /// currently registered under, so an address can be moved to a new
+    /// heap (on overlap) or dropped (on free/realloc) without a linear
+    /// scan of `live_heaps`.
+    owner: HashMap<u64, u64>,
+    /// Addresses vacated by a `Realloc` that moved them elsewhere (i.e.
+    /// `new_address != old_address`). A `Free` of one of these addresses is
+    /// a `UseAfterFree` rather than a `DoubleFree`: the memory was real, it
+    /// just isn't at that address anymore. Cleared once the address is
+    /// legitimately reused by a later `Alloc`/`Realloc`.
+    moved_by_realloc: HashSet<u64>,
+}
+
+impl HeapState {
+    /// Replay `actions` in order, reconstructing the live heap and
+    /// collecting every leak, double-free, use-after-free, and overlap
+    /// found along the way.
+    pub fn replay(actions: &[(HeapAction, Stack)]) -> Vec<HeapError> {
+        let mut state  = HeapState::default();
+        let mut errors = Vec::new();
+
+        for (action, stack) in actions {
+            state.apply(*action, stack.clone(), &mut errors);
+        }
+
+        // Anything still live at the end of the stream is a leak.
+        for (address, (size, stack)) in state.live_addresses.drain() {
+            let heap = state.owner.remove(&address).unwrap_or(0);
+            errors.push(HeapError::Leak { heap, address, size, stack });
+        }
+
+        errors
+    }
+
+    fn apply(&mut self, action: HeapAction, stack: Stack, errors: &mut Vec<HeapError>) {
+        match action {
+            HeapAction::Create { heap } => {
+                self.live_heaps.entry(heap).or_insert_with(HashSet::new);
+            }
+            HeapAction::Destroy { heap } => {
+                if let Some(addresses) = self.live_heaps.remove(&heap) {
+                    for address in addresses {
+                        self.owner.remove(&address);
+                        if let Some((size, alloc_stack)) = self.live_addresses.remove(&address) {
+                            errors.push(HeapError::Leak { heap, address, size, stack: alloc_stack });
+                        }
+                    }
+                }
+            }
+            HeapAction::Alloc { heap, address, size } => {
+                if let Some(prev_heap) = self.owner.get(&address).copied() {
+                    errors.push(HeapError::Overlap { heap, address, size, stack: stack.clone() });
+                    if let Some(addresses) = self.live_heaps.get_mut(&prev_heap) {
+                        addresses.remove(&address);
+                    }
+                }
+                self.moved_by_realloc.remove(&address);
+                self.live_addresses.insert(address, (size, stack));
+                self.live_heaps.entry(heap).or_insert_with(HashSet::new).insert(address);
+                self.owner.insert(address, heap);
+            }
+            HeapAction::Free { heap, address } => {
+                if self.live_addresses.remove(&address).is_none() {
+                    if self.moved_by_realloc.remove(&address) {
+                        errors.push(HeapError::UseAfterFree { heap, address, stack });
+                    } else {
+                        errors.push(HeapError::DoubleFree { heap, address, stack });
+                    }
+                } else {
+                    let owner_heap = self.owner.remove(&address).unwrap_or(heap);
+                    if let Some(addresses) = self.live_heaps.get_mut(&owner_heap) {
+                        addresses.remove(&address);
+                    }
+                }
+            }
+            HeapAction::Realloc { heap, new_address, old_address, new_size, old_size: _ } => {
+                if self.live_addresses.remove(&old_address).is_none() {
+                    errors.push(HeapError::UseAfterFree { heap, address: old_address, stack: stack.clone() });
+                } else {
+                    let owner_heap = self.owner.remove(&old_address).unwrap_or(heap);
+                    if let Some(addresses) = self.live_heaps.get_mut(&owner_heap) {
+                        addresses.remove(&old_address);
+                    }
+                    if new_address != old_address {
+                        self.moved_by_realloc.insert(old_address);
+                    }
+                }
+
+                self.moved_by_realloc.remove(&new_address);
+                self.live_addresses.insert(new_address, (new_size, stack));
+                self.live_heaps.entry(heap).or_insert_with(HashSet::new).insert(new_address);
+                self.owner.insert(new_address, heap);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack() -> Stack {
+        Stack(vec!["main".to_string()])
+    }
+
+    #[test]
+    fn leak_is_reported_when_heap_is_destroyed() {
+        let actions = vec![
+            (HeapAction::Create { heap: 1 }, stack()),
+            (HeapAction::Alloc { heap: 1, address: 0x1000, size: 16 }, stack()),
+            (HeapAction::Destroy { heap: 1 }, stack()),
+        ];
+
+        let errors = HeapState::replay(&actions);
+        assert!(matches!(
+            errors.as_slice(),
+            [HeapError::Leak { heap: 1, address: 0x1000, size: 16, .. }]
+        ));
+    }
+
+    #[test]
+    fn leak_is_reported_at_end_of_stream_if_heap_is_never_destroyed() {
+        let actions = vec![
+            (HeapAction::Create { heap: 1 }, stack()),
+            (HeapAction::Alloc { heap: 1, address: 0x1000, size: 16 }, stack()),
+        ];
+
+        let errors = HeapState::replay(&actions);
+        assert!(matches!(
+            errors.as_slice(),
+            [HeapError::Leak { heap: 1, address: 0x1000, size: 16, .. }]
+        ));
+    }
+
+    #[test]
+    fn double_free_is_reported_without_a_matching_alloc() {
+        let actions = vec![
+            (HeapAction::Create { heap: 1 }, stack()),
+            (HeapAction::Free { heap: 1, address: 0x1000 }, stack()),
+        ];
+
+        let errors = HeapState::replay(&actions);
+        assert!(matches!(
+            errors.as_slice(),
+            [HeapError::DoubleFree { heap: 1, address: 0x1000, .. }]
+        ));
+    }
+
+    #[test]
+    fn overlapping_alloc_moves_ownership_so_the_stale_heap_reports_no_leak() {
+        // Address 0x1000 is allocated under heap 1, then allocated again
+        // under heap 2 without being freed first (an overlap). Heap 1 is
+        // destroyed afterwards and must NOT report a leak for an address it
+        // no longer owns; heap 2 does own it and should leak at EOF.
+        let actions = vec![
+            (HeapAction::Create { heap: 1 }, stack()),
+            (HeapAction::Create { heap: 2 }, stack()),
+            (HeapAction::Alloc { heap: 1, address: 0x1000, size: 16 }, stack()),
+            (HeapAction::Alloc { heap: 2, address: 0x1000, size: 32 }, stack()),
+            (HeapAction::Destroy { heap: 1 }, stack()),
+        ];
+
+        let errors = HeapState::replay(&actions);
+
+        let overlaps: Vec<_> = errors.iter().filter(|e| matches!(e, HeapError::Overlap { .. })).collect();
+        assert_eq!(overlaps.len(), 1);
+
+        let leaks: Vec<_> = errors.iter().filter(|e| matches!(e, HeapError::Leak { .. })).collect();
+        assert!(matches!(
+            leaks.as_slice(),
+            [HeapError::Leak { heap: 2, address: 0x1000, size: 32, .. }]
+        ));
+    }
+
+    #[test]
+    fn freeing_an_address_a_realloc_moved_away_from_is_use_after_free_not_double_free() {
+        let actions = vec![
+            (HeapAction::Create { heap: 1 }, stack()),
+            (HeapAction::Alloc { heap: 1, address: 0x1000, size: 16 }, stack()),
+            (HeapAction::Realloc {
+                heap: 1, new_address: 0x2000, old_address: 0x1000, new_size: 32, old_size: 16,
+            }, stack()),
+            // 0x1000 isn't live anywhere anymore - it was moved to 0x2000 -
+            // so freeing it again is a use of already-moved memory, not an
+            // unrelated double free.
+            (HeapAction::Free { heap: 1, address: 0x1000 }, stack()),
+        ];
+
+        let errors = HeapState::replay(&actions);
+        assert!(matches!(
+            errors.as_slice(),
+            [HeapError::UseAfterFree { heap: 1, address: 0x1000, .. }, HeapError::Leak { heap: 1, address: 0x2000, .. }]
+        ));
+    }
+
+    #[test]
+    fn reallocating_in_place_does_not_flag_a_later_free_as_use_after_free() {
+        // new_address == old_address: nothing moved away, so freeing that
+        // same address later is an ordinary, valid free.
+        let actions = vec![
+            (HeapAction::Create { heap: 1 }, stack()),
+            (HeapAction::Alloc { heap: 1, address: 0x1000, size: 16 }, stack()),
+            (HeapAction::Realloc {
+                heap: 1, new_address: 0x1000, old_address: 0x1000, new_size: 32, old_size: 16,
+            }, stack()),
+            (HeapAction::Free { heap: 1, address: 0x1000 }, stack()),
+        ];
+
+        assert_eq!(HeapState::replay(&actions).len(), 0);
+    }
+}
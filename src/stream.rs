@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::io::BufRead;
+
+use crate::error::ParseError;
+use crate::text::{self, HeaderState};
+use crate::{HeapAction, Stack};
+
+/// Streams `(HeapAction, Stack)` pairs out of an `xperf -i` text dump as it
+/// is read, without ever holding the whole trace or the full activity/stack
+/// lists in memory at once.
+///
+/// Built from any `impl BufRead`, reading one line at a time and performing
+/// lossy UTF-8 conversion per line (rather than over the whole file up
+/// front). Yields `Err((line, error))` for a line that could not be parsed;
+/// wrap this iterator with [`crate::parse_lenient`]'s logic (skip and
+/// record) or propagate the first error with `collect::<Result<_, _>>()`.
+pub struct HeapActivity<R> {
+    reader:         R,
+    line_buf:       Vec<u8>,
+    line_no:        usize,
+    header:         HeaderState,
+    ready_to_parse: bool,
+    pending:        VecDeque<HeapAction>,
+    active_stack:   Stack,
+    exhausted:      bool,
+}
+
+/// Wraps `reader` in a streaming iterator of parsed heap activity. See
+/// [`HeapActivity`].
+pub fn parse_reader<R: BufRead>(reader: R) -> HeapActivity<R> {
+    HeapActivity {
+        reader,
+        line_buf:       Vec::new(),
+        line_no:        0,
+        header:         HeaderState::default(),
+        ready_to_parse: false,
+        pending:        VecDeque::new(),
+        active_stack:   Stack(Vec::new()),
+        exhausted:      false,
+    }
+}
+
+impl<R: BufRead> Iterator for HeapActivity<R> {
+    type Item = Result<(HeapAction, Stack), (usize, ParseError)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.exhausted {
+                // Drain any actions that never got a matching stack before
+                // EOF (e.g. a capture truncated mid-event). Only the first
+                // one gets whatever trailing frames were accumulated; the
+                // rest get an empty `Stack` rather than being dropped.
+                return self.pending.pop_front().map(|action| {
+                    let stack = std::mem::replace(&mut self.active_stack, Stack(Vec::new()));
+                    Ok((action, stack))
+                });
+            }
+
+            self.line_buf.clear();
+            let read = match self.reader.read_until(b'\n', &mut self.line_buf) {
+                Ok(read) => read,
+                Err(err) => {
+                    self.exhausted = true;
+                    self.line_no += 1;
+                    return Some(Err((self.line_no, ParseError::Io(err))));
+                }
+            };
+            if read == 0 {
+                self.exhausted = true;
+                continue;
+            }
+            self.line_no += 1;
+
+            let line = String::from_utf8_lossy(&self.line_buf);
+            let line = line.trim_end_matches(['\r', '\n']);
+            let columns: Vec<&str> = line.split(",").map(|x| x.trim()).collect();
+
+            if columns.len() == 0 { continue; }
+
+            if !self.ready_to_parse {
+                match self.header.observe(&columns) {
+                    Ok(ready) => self.ready_to_parse = ready,
+                    Err(err) => return Some(Err((self.line_no, err))),
+                }
+                continue;
+            }
+
+            match text::parse_columns(&columns) {
+                Err(err) => return Some(Err((self.line_no, err))),
+                Ok(text::ParsedLine::Action(action)) => {
+                    self.pending.push_back(action);
+                }
+                Ok(text::ParsedLine::StackFrame { depth, symbol }) => {
+                    // Reset stack if depth is 1
+                    if depth == 1 {
+                        if self.active_stack.0.len() > 0 {
+                            let stack = std::mem::replace(&mut self.active_stack, Stack(Vec::new()));
+                            if let Some(action) = self.pending.pop_front() {
+                                self.active_stack.0.push(symbol);
+                                return Some(Ok((action, stack)));
+                            }
+                        }
+                    }
+
+                    self.active_stack.0.push(symbol);
+                }
+                Ok(text::ParsedLine::Ignored) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: &str = "\
+HeapCreate, TimeStamp, Process Name ( PID), ThreadID, HeapHandle, Flags, ReserveSize, CommitSize, AllocatedSize
+HeapDestroy, TimeStamp, Process Name ( PID), ThreadID, HeapHandle
+HeapAlloc, TimeStamp, Process Name ( PID), ThreadID, HeapHandle, Address, Size, Source
+HeapFree, TimeStamp, Process Name ( PID), ThreadID, HeapHandle, Address, __Reserved, Source
+HeapRealloc, TimeStamp, Process Name ( PID), ThreadID, HeapHandle, NewAddress, OldAddress, NewSize, OldSize, Source
+Stack, TimeStamp, ThreadID, No., Address, Image!Function
+EndHeader
+";
+
+    fn collect(input: &str) -> Vec<(HeapAction, Stack)> {
+        parse_reader(Cursor::new(input.as_bytes().to_vec()))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn a_single_action_is_paired_with_its_stack() {
+        let input = format!(
+            "{HEADER}HeapCreate, 0, proc (1), 1, 0x1, 0, 0, 0, 0\nStack, 0, 1, 1, 0x0, main\n"
+        );
+        let result = collect(&input);
+        assert_eq!(result.len(), 1);
+        assert!(matches!(result[0].0, HeapAction::Create { heap: 1 }));
+        assert_eq!(result[0].1.0, vec!["main".to_string()]);
+    }
+
+    #[test]
+    fn back_to_back_actions_with_no_stack_before_eof_are_all_still_returned() {
+        // HeapCreate and HeapDestroy both queue up in `pending` with no
+        // intervening Stack row before the file ends. Every action must
+        // still come out, even if it gets an empty stack.
+        let input = format!(
+            "{HEADER}HeapCreate, 0, proc (1), 1, 0x1, 0, 0, 0, 0\nHeapDestroy, 0, proc (1), 1, 0x1\n"
+        );
+        let result = collect(&input);
+
+        assert_eq!(result.len(), 2);
+        assert!(matches!(result[0].0, HeapAction::Create { heap: 1 }));
+        assert!(matches!(result[1].0, HeapAction::Destroy { heap: 1 }));
+    }
+}
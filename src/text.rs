@@ -0,0 +1,131 @@
+use crate::error::ParseError;
+use crate::HeapAction;
+
+// These are the expected formats for the different events we care about
+// make sure they match so we do not end up parsing the wrong fields
+const HEAPCREATE_FORMAT:  &[&str] = &["HeapCreate", "TimeStamp", "Process Name ( PID)", "ThreadID", "HeapHandle", "Flags", "ReserveSize", "CommitSize", "AllocatedSize"];
+const HEAPDESTROY_FORMAT: &[&str] = &["HeapDestroy", "TimeStamp", "Process Name ( PID)", "ThreadID", "HeapHandle"];
+const HEAPALLOC_FORMAT:   &[&str] = &["HeapAlloc", "TimeStamp", "Process Name ( PID)", "ThreadID", "HeapHandle", "Address", "Size", "Source"];
+const HEAPFREE_FORMAT:    &[&str] = &["HeapFree", "TimeStamp", "Process Name ( PID)", "ThreadID", "HeapHandle", "Address", "__Reserved", "Source"];
+const HEAPREALLOC_FORMAT: &[&str] = &["HeapRealloc", "TimeStamp", "Process Name ( PID)", "ThreadID", "HeapHandle", "NewAddress", "OldAddress", "NewSize", "OldSize", "Source"];
+const STACK_FORMAT:       &[&str] = &["Stack", "TimeStamp", "ThreadID", "No.", "Address", "Image!Function"];
+
+/// Parse a hex number as a string with an 0x prefix
+pub(crate) fn parse_hex(string: &str) -> Result<u64, ParseError> {
+    if !string.starts_with("0x") {
+        return Err(ParseError::InvalidHexPrefix { column: string.into() });
+    }
+    u64::from_str_radix(&string[2..], 16)
+        .map_err(|_| ParseError::InvalidHexNumber { column: string.into() })
+}
+
+/// Tracks which column headers we've seen while scanning the preamble of an
+/// `xperf -i` text dump, so we can confirm we're parsing the version of the
+/// format we expect before we start trusting column offsets.
+#[derive(Default)]
+pub(crate) struct HeaderState {
+    heapcreate_matches:  bool,
+    heapdestroy_matches: bool,
+    heapalloc_matches:   bool,
+    heapfree_matches:    bool,
+    heaprealloc_matches: bool,
+    stack_matches:       bool,
+}
+
+impl HeaderState {
+    /// Feed one preamble line's columns in. Returns `Ok(true)` once
+    /// `EndHeader` has been seen and every expected header matched, meaning
+    /// the caller should switch to parsing event rows.
+    pub(crate) fn observe(&mut self, columns: &[&str]) -> Result<bool, ParseError> {
+        if columns == HEAPALLOC_FORMAT {
+            self.heapalloc_matches = true;
+        } else if columns == HEAPFREE_FORMAT {
+            self.heapfree_matches = true;
+        } else if columns == HEAPREALLOC_FORMAT {
+            self.heaprealloc_matches = true;
+        } else if columns == HEAPCREATE_FORMAT {
+            self.heapcreate_matches = true;
+        } else if columns == HEAPDESTROY_FORMAT {
+            self.heapdestroy_matches = true;
+        } else if columns == STACK_FORMAT {
+            self.stack_matches = true;
+        } else if columns == ["EndHeader"] {
+            if !(self.heapalloc_matches && self.heapfree_matches &&
+                self.heaprealloc_matches && self.heapcreate_matches &&
+                self.heapdestroy_matches && self.stack_matches)
+            {
+                return Err(ParseError::MissingHeaders);
+            }
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+}
+
+/// The result of parsing a single event row once we're past the preamble.
+pub(crate) enum ParsedLine {
+    Action(HeapAction),
+    StackFrame { depth: u64, symbol: String },
+    Ignored,
+}
+
+/// Requires `columns` to have at least `expected` entries for the named
+/// `event`, so the column indexing below can't panic on a truncated row.
+fn require_columns(event: &str, columns: &[&str], expected: usize) -> Result<(), ParseError> {
+    if columns.len() < expected {
+        return Err(ParseError::MissingColumns {
+            event: event.into(), expected, found: columns.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Parses one event row's columns into a `HeapAction` or a stack frame.
+pub(crate) fn parse_columns(columns: &[&str]) -> Result<ParsedLine, ParseError> {
+    match columns[0] {
+        "HeapCreate" => {
+            require_columns("HeapCreate", columns, 5)?;
+            let heap = parse_hex(columns[4])?;
+            Ok(ParsedLine::Action(HeapAction::Create { heap }))
+        }
+        "HeapDestroy" => {
+            require_columns("HeapDestroy", columns, 5)?;
+            let heap = parse_hex(columns[4])?;
+            Ok(ParsedLine::Action(HeapAction::Destroy { heap }))
+        }
+        "HeapAlloc" => {
+            require_columns("HeapAlloc", columns, 7)?;
+            let heap    = parse_hex(columns[4])?;
+            let address = parse_hex(columns[5])?;
+            let size    = parse_hex(columns[6])?;
+            Ok(ParsedLine::Action(HeapAction::Alloc { heap, address, size }))
+        }
+        "HeapFree" => {
+            require_columns("HeapFree", columns, 6)?;
+            let heap    = parse_hex(columns[4])?;
+            let address = parse_hex(columns[5])?;
+            Ok(ParsedLine::Action(HeapAction::Free { heap, address }))
+        }
+        "HeapRealloc" => {
+            require_columns("HeapRealloc", columns, 9)?;
+            let heap        = parse_hex(columns[4])?;
+            let new_address = parse_hex(columns[5])?;
+            let old_address = parse_hex(columns[6])?;
+            let new_size    = parse_hex(columns[7])?;
+            let old_size    = parse_hex(columns[8])?;
+            Ok(ParsedLine::Action(HeapAction::Realloc {
+                heap, new_address, old_address, new_size, old_size
+            }))
+        }
+        "Stack" => {
+            require_columns("Stack", columns, 6)?;
+            let depth: u64 = columns[3].parse()
+                .map_err(|_| ParseError::InvalidDepth { column: columns[3].into() })?;
+            let _address = parse_hex(columns[4])?;
+            let symbol   = columns[5];
+            Ok(ParsedLine::StackFrame { depth, symbol: symbol.into() })
+        }
+        _ => Ok(ParsedLine::Ignored),
+    }
+}